@@ -0,0 +1,90 @@
+use chrono::{Duration, NaiveDate, NaiveTime};
+use std::collections::BTreeMap;
+use timespan::NaiveTimeSpan;
+
+const ROW_LENGTH_MINUTES: i64 = 30;
+
+/// Renders the filtered courts as a self-contained HTML page: courts as
+/// columns, 30-minute rows spanning the min/max of the day, with each free
+/// span drawn as a colored block.
+pub fn build_html(
+    filtered_available: &BTreeMap<u32, Vec<NaiveTimeSpan>>,
+    desired_date: NaiveDate,
+) -> String {
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str(&format!(
+        "<title>Free courts on {}</title>\n",
+        desired_date
+    ));
+    html.push_str(
+        "<style>\n\
+         table { border-collapse: collapse; font-family: sans-serif; }\n\
+         th, td { border: 1px solid #ccc; padding: 4px 8px; text-align: center; }\n\
+         td.free { background-color: #8BC34A; }\n\
+         </style>\n</head>\n<body>\n",
+    );
+
+    if filtered_available.is_empty() {
+        html.push_str("<p>No free courts found.</p>\n</body>\n</html>\n");
+        return html;
+    }
+
+    let (day_start, day_end) = day_bounds(filtered_available);
+    let courts: Vec<&u32> = filtered_available.keys().collect();
+
+    html.push_str("<table>\n<tr><th>Time</th>");
+    for court in &courts {
+        html.push_str(&format!("<th>Court {}</th>", court));
+    }
+    html.push_str("</tr>\n");
+
+    let mut row_start = day_start;
+    while row_start < day_end {
+        let row_end = row_start + Duration::minutes(ROW_LENGTH_MINUTES);
+        html.push_str(&format!(
+            "<tr><td>{}</td>",
+            row_start.format("%H:%M")
+        ));
+
+        for court in &courts {
+            let is_free = filtered_available[*court]
+                .iter()
+                .any(|slot| slot.start <= row_start && slot.end >= row_end);
+            if is_free {
+                html.push_str("<td class=\"free\"></td>");
+            } else {
+                html.push_str("<td></td>");
+            }
+        }
+
+        html.push_str("</tr>\n");
+        row_start = row_end;
+    }
+
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+/// Writes a built HTML page to `path`.
+pub fn write_html_file(path: &str, html: &str) -> std::io::Result<()> {
+    std::fs::write(path, html)
+}
+
+fn day_bounds(filtered_available: &BTreeMap<u32, Vec<NaiveTimeSpan>>) -> (NaiveTime, NaiveTime) {
+    let mut min_start = NaiveTime::from_hms_opt(23, 59, 0).unwrap();
+    let mut max_end = NaiveTime::from_hms_opt(0, 0, 0).unwrap();
+
+    for slots in filtered_available.values() {
+        for slot in slots {
+            if slot.start < min_start {
+                min_start = slot.start;
+            }
+            if slot.end > max_end {
+                max_end = slot.end;
+            }
+        }
+    }
+
+    (min_start, max_end)
+}
@@ -0,0 +1,54 @@
+use crate::Activity;
+use chrono::{NaiveDate, NaiveDateTime};
+use std::collections::BTreeMap;
+use std::io;
+use timespan::NaiveTimeSpan;
+
+/// Builds an RFC5545 iCalendar document where each free slot in `filtered_available`
+/// becomes a VEVENT on `desired_date`, with `DTSTART`/`DTEND` from the slot's
+/// start/end and a `UID` derived from the activity, court and start time.
+pub fn build_ics(
+    filtered_available: &BTreeMap<u32, Vec<NaiveTimeSpan>>,
+    activity: &Activity,
+    desired_date: NaiveDate,
+) -> String {
+    let mut ics = String::new();
+    ics.push_str("BEGIN:VCALENDAR\r\n");
+    ics.push_str("VERSION:2.0\r\n");
+    ics.push_str("PRODID:-//zhsbot//zhsbot//EN\r\n");
+    ics.push_str("CALSCALE:GREGORIAN\r\n");
+
+    for (court, timeslots) in filtered_available {
+        for slot in timeslots {
+            let dtstart = NaiveDateTime::new(desired_date, slot.start);
+            let dtend = NaiveDateTime::new(desired_date, slot.end);
+
+            ics.push_str("BEGIN:VEVENT\r\n");
+            ics.push_str(&format!(
+                "UID:{}-{}-{}@zhsbot\r\n",
+                activity,
+                court,
+                dtstart.format("%Y%m%dT%H%M%S")
+            ));
+            ics.push_str(&format!(
+                "DTSTART:{}\r\n",
+                dtstart.format("%Y%m%dT%H%M%S")
+            ));
+            ics.push_str(&format!("DTEND:{}\r\n", dtend.format("%Y%m%dT%H%M%S")));
+            ics.push_str(&format!(
+                "SUMMARY:Free: {} Court {}\r\n",
+                activity.name(),
+                court
+            ));
+            ics.push_str("END:VEVENT\r\n");
+        }
+    }
+
+    ics.push_str("END:VCALENDAR\r\n");
+    ics
+}
+
+/// Writes a built iCalendar document to `path`.
+pub fn write_ics_file(path: &str, ics: &str) -> io::Result<()> {
+    std::fs::write(path, ics)
+}
@@ -1,7 +1,8 @@
 use chrono::{NaiveDate, NaiveTime};
 use clap::Parser;
 use reqwest::{
-    blocking::{get, Client, Response},
+    blocking::{Client, Response},
+    header::RETRY_AFTER,
     Error,
 };
 use scraper::{selectable::Selectable, Html, Selector};
@@ -9,24 +10,63 @@ use std::{
     cmp::Ordering,
     collections::BTreeMap,
     fmt::{self},
+    sync::OnceLock,
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant},
 };
 use timespan::NaiveTimeSpan;
 
+mod html;
+mod ical;
+
+const ZHS_BASE_URL: &str = "https://zhs-courtbuchung.de/";
+const USER_AGENT: &str = "zhsbot/1.0 (+https://github.com/Jonas164/zhsbot)";
+const MAX_REQUEST_ATTEMPTS: u32 = 5;
+const BASE_BACKOFF_SECS: u64 = 5;
+const MAX_BACKOFF_SECS: u64 = 20;
+
+#[derive(clap::ValueEnum, Clone, Debug, PartialEq, Eq)]
 enum Activity {
     BEACH,
     TENNIS,
     PICKLE,
 }
 
+impl Activity {
+    /// Human-readable name used in notifications and calendar exports.
+    fn name(&self) -> &'static str {
+        match self {
+            Activity::TENNIS => "Tennis",
+            Activity::BEACH => "Beach Volleyball",
+            Activity::PICKLE => "Pickleball",
+        }
+    }
+}
+
+impl std::str::FromStr for Activity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "tennis" => Ok(Activity::TENNIS),
+            "beach" => Ok(Activity::BEACH),
+            "pickle" => Ok(Activity::PICKLE),
+            other => Err(format!("unknown activity: {}", other)),
+        }
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    //TODO let user decide which activity
-    /// Date that should be watched for open courts
+    /// Activity to watch for. Used with --date; --watch entries specify their own activity.
+    #[arg(long, value_enum, default_value = "tennis")]
+    activity: Activity,
+
+    /// Date that should be watched for open courts. Combined with --activity/--after/--before/--length
+    /// into a single watch; omit this and use --watch instead to watch several requests at once.
     #[arg(short, long)]
-    date: String,
+    date: Option<String>,
 
     //Exclusive After-Time. If you want a court at 14:00, type 13:30 here. Format is HH:MM
     #[arg(long)]
@@ -39,6 +79,33 @@ struct Args {
     //Minimal time in minutes that should be available.
     #[arg(short, long)]
     length: Option<String>,
+
+    /// Write an iCalendar (.ics) feed of the found slots next to this path; the
+    /// watch's activity and date are inserted before the extension
+    /// (e.g. "out.ics" becomes "out.tennis.2024-12-24.ics")
+    #[arg(long)]
+    export: Option<String>,
+
+    /// Write a standalone HTML calendar of the found slots next to this path; the
+    /// watch's activity and date are inserted before the extension
+    /// (e.g. "out.html" becomes "out.tennis.2024-12-24.html")
+    #[arg(long)]
+    html: Option<String>,
+
+    /// ZHS username. When given together with --pass, the bot logs in and
+    /// books the first matching slot instead of only notifying.
+    #[arg(long)]
+    user: Option<String>,
+
+    /// ZHS password, used together with --user
+    #[arg(long)]
+    pass: Option<String>,
+
+    /// Watch an additional activity/date/time-window concurrently, as
+    /// "activity,date,after,before,length" (e.g. "tennis,24.12.2024,18:00,23:00,90").
+    /// May be given multiple times to watch several requests at once.
+    #[arg(long)]
+    watch: Vec<String>,
 }
 
 impl fmt::Display for Activity {
@@ -60,21 +127,17 @@ struct Notifier {
 struct UrlBuilder;
 
 impl Notifier {
-    fn notify(&self, message: String) -> Response {
+    fn notify(&self, message: String) -> Result<Response, Error> {
         let url = format!("{}{}", self.base_url, self.topic);
-        let res = self.client.post(url).body(message).send();
-
-        match res {
-            Ok(response) => return response,
-            Err(e) => panic!("{}", e),
-        }
+        self.client.post(url).body(message).send()
     }
 }
 
 impl UrlBuilder {
     fn build_request_url(&self, activity: &Activity, date: &String, page_num: u8) -> String {
         return format!(
-            "https://zhs-courtbuchung.de/reservations.php?action=showRevervations&type_id={type}&date={date}&page={page_num}",
+            "{base_url}reservations.php?action=showRevervations&type_id={type}&date={date}&page={page_num}",
+            base_url = ZHS_BASE_URL,
             type = activity.to_string(),
             date = date,
             page_num = page_num.to_string()
@@ -88,6 +151,161 @@ struct Defaults {
     length: String,
 }
 
+/// One watched activity/date/time-window request, as produced by a `--date`
+/// (plus `--after`/`--before`/`--length`) invocation or a single `--watch` entry.
+struct WatchSpec {
+    activity: Activity,
+    date: NaiveDate,
+    after: NaiveTime,
+    before: NaiveTime,
+    length: i64,
+}
+
+impl WatchSpec {
+    /// Parses a `"activity,date,after,before,length"` `--watch` argument.
+    fn parse(spec: &str, date_fmt: &str, time_fmt: &str) -> WatchSpec {
+        let parts: Vec<&str> = spec.split(',').map(str::trim).collect();
+        if parts.len() != 5 {
+            panic!(
+                "--watch must look like \"activity,date,after,before,length\", got \"{}\"",
+                spec
+            );
+        }
+
+        WatchSpec {
+            activity: parts[0]
+                .parse()
+                .unwrap_or_else(|e| panic!("invalid --watch: {}", e)),
+            date: NaiveDate::parse_from_str(parts[1], date_fmt).unwrap(),
+            after: NaiveTime::parse_from_str(parts[2], time_fmt).unwrap(),
+            before: NaiveTime::parse_from_str(parts[3], time_fmt).unwrap(),
+            length: parts[4].parse().expect("length must be a number of minutes"),
+        }
+    }
+
+    /// Namespaces an `--export`/`--html` path by this watch's activity and date,
+    /// so several concurrently-satisfied watches don't overwrite each other's file.
+    fn namespace_path(&self, base: &str) -> String {
+        let suffix = format!(
+            "{}.{}",
+            self.activity.name().to_lowercase().replace(' ', "-"),
+            self.date.format("%Y-%m-%d")
+        );
+
+        let path = std::path::Path::new(base);
+        let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or(base);
+        let namespaced_file = match file_name.rsplit_once('.') {
+            Some((stem, ext)) => format!("{}.{}.{}", stem, suffix, ext),
+            None => format!("{}.{}", file_name, suffix),
+        };
+
+        match path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            Some(dir) => dir.join(namespaced_file).to_string_lossy().into_owned(),
+            None => namespaced_file,
+        }
+    }
+}
+
+/// A `WatchSpec` waiting in the scheduler's run-queue for its next poll.
+struct ScheduledWatch {
+    next_poll: Instant,
+    spec: WatchSpec,
+}
+
+/// Error from a login or reservation request: either the request itself
+/// failed (transport error or non-success HTTP status), or the server
+/// answered with 200 but rejected it (wrong credentials, slot already
+/// taken) — reqwest alone can't tell these apart, so we inspect the body.
+#[derive(Debug)]
+enum SessionError {
+    Request(Error),
+    Rejected(String),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::Request(e) => write!(f, "{}", e),
+            SessionError::Rejected(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl From<Error> for SessionError {
+    fn from(e: Error) -> Self {
+        SessionError::Request(e)
+    }
+}
+
+/// A logged-in ZHS session. Holds a `Client` with a cookie store so the
+/// session cookie returned by the login POST is kept and sent along with
+/// the reservation POST.
+struct Session {
+    base_url: String,
+    client: Client,
+}
+
+impl Session {
+    fn login(base_url: &str, user: &str, pass: &str) -> Result<Session, SessionError> {
+        let client = Client::builder()
+            .cookie_store(true)
+            .user_agent(USER_AGENT)
+            .build()?;
+
+        let response = client
+            .post(format!("{}reservations.php", base_url))
+            .form(&[("action", "login"), ("username", user), ("password", pass)])
+            .send()?
+            .error_for_status()?;
+
+        // A rejected login re-renders the login form instead of redirecting past it.
+        let body = response.text()?;
+        if body.contains("name=\"password\"") {
+            return Err(SessionError::Rejected(
+                "login rejected: credentials page was shown again".to_string(),
+            ));
+        }
+
+        Ok(Session {
+            base_url: base_url.to_string(),
+            client,
+        })
+    }
+
+    /// Books `court` on `date` for `slot`, the way a real user clicking the
+    /// reservation button would submit it.
+    fn reserve(
+        &self,
+        activity: &Activity,
+        date: &str,
+        court: u32,
+        slot: &NaiveTimeSpan,
+    ) -> Result<(), SessionError> {
+        let response = self
+            .client
+            .post(format!("{}reservations.php", self.base_url))
+            .form(&[
+                ("action", "reserve".to_string()),
+                ("type_id", activity.to_string()),
+                ("date", date.to_string()),
+                ("court", court.to_string()),
+                ("start", slot.start.format("%H:%M").to_string()),
+                ("end", slot.end.format("%H:%M").to_string()),
+            ])
+            .send()?
+            .error_for_status()?;
+
+        let body = response.text()?;
+        if body.to_lowercase().contains("fehler") {
+            return Err(SessionError::Rejected(
+                "reservation rejected by server".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
 fn main() {
     let time_fmt = "%H:%M";
     let date_fmt = "%d.%m.%Y";
@@ -105,49 +323,147 @@ fn main() {
 
     let args = Args::parse();
 
-    let desired_date = NaiveDate::parse_from_str(&args.date, &date_fmt).unwrap();
-    let desired_after_time =
-        NaiveTime::parse_from_str(&args.after.unwrap_or(defaults.after), &time_fmt).unwrap();
-    let desired_before_time =
-        NaiveTime::parse_from_str(&args.before.unwrap_or(defaults.before), &time_fmt).unwrap();
+    let session = match (&args.user, &args.pass) {
+        (Some(user), Some(pass)) => {
+            Some(Session::login(ZHS_BASE_URL, user, pass).expect("login failed"))
+        }
+        _ => None,
+    };
+
+    let mut watches: Vec<WatchSpec> = args
+        .watch
+        .iter()
+        .map(|spec| WatchSpec::parse(spec, date_fmt, time_fmt))
+        .collect();
+
+    if let Some(date) = &args.date {
+        watches.push(WatchSpec {
+            activity: args.activity.clone(),
+            date: NaiveDate::parse_from_str(date, date_fmt).unwrap(),
+            after: NaiveTime::parse_from_str(&args.after.unwrap_or(defaults.after), &time_fmt)
+                .unwrap(),
+            before: NaiveTime::parse_from_str(&args.before.unwrap_or(defaults.before), &time_fmt)
+                .unwrap(),
+            length: args
+                .length
+                .unwrap_or(defaults.length)
+                .parse()
+                .expect("length must be a number of minutes"),
+        });
+    }
+
+    if watches.is_empty() {
+        panic!("Provide --date (optionally with --after/--before/--length), or at least one --watch");
+    }
 
     println!(
-        "Searching for open courts on {} after {} and before {}, checking every 5s",
-        desired_date, desired_after_time, desired_before_time
+        "Watching {} request(s), checking every 5s",
+        watches.len()
     );
 
-    do_search(
-        desired_date,
-        desired_after_time,
-        desired_before_time,
-        notifier,
-    );
+    do_search(watches, notifier, args.export, args.html, session);
 }
 
 fn do_search(
-    desired_date: NaiveDate,
-    desired_after_time: NaiveTime,
-    desired_before_time: NaiveTime,
+    watches: Vec<WatchSpec>,
     notifier: Notifier,
+    export_path: Option<String>,
+    html_path: Option<String>,
+    session: Option<Session>,
 ) {
-    loop {
-        let available_times_per_court =
-            query_and_parse(&Activity::TENNIS, &desired_date.to_string());
+    let poll_interval = Duration::from_secs(5);
+    let mut queue: Vec<ScheduledWatch> = watches
+        .into_iter()
+        .map(|spec| ScheduledWatch {
+            next_poll: Instant::now(),
+            spec,
+        })
+        .collect();
+
+    while !queue.is_empty() {
+        let next_index = queue
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, watch)| watch.next_poll)
+            .map(|(index, _)| index)
+            .unwrap();
+
+        let now = Instant::now();
+        if queue[next_index].next_poll > now {
+            sleep(queue[next_index].next_poll - now);
+        }
+
+        let ScheduledWatch { spec, .. } = queue.remove(next_index);
+
+        let available_times_per_court = query_and_parse(&spec.activity, &spec.date.to_string());
 
         match available_times_per_court {
             Some(available_times) => {
                 let filtered =
-                    filter_courts(available_times, desired_after_time, desired_before_time);
+                    filter_courts(available_times, spec.after, spec.before, spec.length);
                 if !filtered.is_empty() {
+                    println!("Found a match for {} on {}", spec.activity.name(), spec.date);
                     print_all_available_times(&filtered);
-                    notifier.notify(build_result_string(&filtered));
-                    return;
+                    if let Err(e) = notifier.notify(build_result_string(&filtered)) {
+                        println!(
+                            "Failed to send notification for {} on {}: {}",
+                            spec.activity.name(),
+                            spec.date,
+                            e
+                        );
+                    }
+                    if let Some(path) = &export_path {
+                        let path = spec.namespace_path(path);
+                        let ics = ical::build_ics(&filtered, &spec.activity, spec.date);
+                        if let Err(e) = ical::write_ics_file(&path, &ics) {
+                            println!("Failed to write ics export to {}: {}", path, e);
+                        }
+                    }
+                    if let Some(path) = &html_path {
+                        let path = spec.namespace_path(path);
+                        let page = html::build_html(&filtered, spec.date);
+                        if let Err(e) = html::write_html_file(&path, &page) {
+                            println!("Failed to write html export to {}: {}", path, e);
+                        }
+                    }
+                    if let Some(session) = &session {
+                        let (court, slot) = filtered
+                            .iter()
+                            .find_map(|(court, slots)| slots.first().map(|slot| (*court, slot)))
+                            .expect("filtered is non-empty");
+                        match session.reserve(&spec.activity, &spec.date.to_string(), court, slot)
+                        {
+                            Ok(()) => println!("Reserved court {} for {}", court, slot),
+                            Err(e) => println!("Reservation failed: {}", e),
+                        }
+                    }
+                    // Satisfied: drop this watch instead of re-queueing it.
+                } else {
+                    println!(
+                        "Nothing found for {} on {}. Rechecking in {:?}",
+                        spec.activity.name(),
+                        spec.date,
+                        poll_interval
+                    );
+                    queue.push(ScheduledWatch {
+                        next_poll: Instant::now() + poll_interval,
+                        spec,
+                    });
                 }
-                println!("Nothing found. Sleeping 5s")
             }
-            None => panic!("Request or Parsing failed with error"),
+            None => {
+                println!(
+                    "Request or parsing failed for {} on {}, rechecking in {:?}",
+                    spec.activity.name(),
+                    spec.date,
+                    poll_interval
+                );
+                queue.push(ScheduledWatch {
+                    next_poll: Instant::now() + poll_interval,
+                    spec,
+                });
+            }
         }
-        sleep(Duration::from_secs(5));
     }
 }
 
@@ -155,13 +471,16 @@ fn filter_courts(
     available_times: BTreeMap<u32, Vec<timespan::Span<chrono::NaiveTime>>>,
     desired_after_time: NaiveTime,
     desired_before_time: NaiveTime,
+    desired_length_minutes: i64,
 ) -> BTreeMap<u32, Vec<timespan::Span<chrono::NaiveTime>>> {
     let mut filtered_courts: BTreeMap<u32, Vec<timespan::Span<NaiveTime>>> = BTreeMap::new();
 
     for (court, timeslots) in available_times {
         for slot in timeslots {
+            let length_minutes = (slot.end - slot.start).num_minutes();
             if slot.start.cmp(&desired_after_time) == Ordering::Greater
                 && slot.start.cmp(&desired_before_time) == Ordering::Less
+                && length_minutes >= desired_length_minutes
             {
                 //This slot matches
                 filtered_courts.entry(court).or_default().push(slot.clone());
@@ -235,7 +554,7 @@ fn query_and_parse(
                     } else {
                         //For each table column representing one court
                         for court in parsed_dom.select(&court_tablecol_select) {
-                            get_available_times_for_court(court, activity, &mut result_map);
+                            get_available_times_for_court(court, &mut result_map);
                         }
                         page_num += 1;
                     }
@@ -255,12 +574,11 @@ fn query_and_parse(
 
 fn get_available_times_for_court(
     court: scraper::ElementRef,
-    activity: &Activity,
     result_map: &mut BTreeMap<u32, Vec<NaiveTimeSpan>>,
 ) {
     let per_court_available_time_select = Selector::parse("td.avaliable").unwrap();
 
-    let court_num = get_court_num(court, activity);
+    let court_num = get_court_num(court);
 
     let mut available_timestamps = vec![];
     for available_timestamp in court.select(&per_court_available_time_select) {
@@ -278,82 +596,126 @@ fn get_available_times_for_court(
         .map(|s| NaiveTimeSpan::parse_from_str(s.as_str(), "{start} - {end}", "%R", "%R").unwrap())
         .collect();
 
-    // let compacted = compact_timespans(parsed_timespans); //TODO reenable once working
+    let compacted = compact_timespans(parsed_timespans);
 
-    result_map.insert(court_num, parsed_timespans);
+    result_map.insert(court_num, compacted);
 }
 
-fn compact_timespans(parsed_timespans: Vec<NaiveTimeSpan>) -> Vec<NaiveTimeSpan> {
-    //TODO this is buggy and doesnt work at all
-    let mut result: Vec<NaiveTimeSpan> = vec![];
-
-    //Nothing to merge
-    if parsed_timespans.len() == 1 {
+/// Merges adjacent and overlapping spans in `parsed_timespans` into the
+/// fewest spans that cover the same time. Spans must be sorted by `start`
+/// for the fold below to see every merge candidate exactly once.
+fn compact_timespans(mut parsed_timespans: Vec<NaiveTimeSpan>) -> Vec<NaiveTimeSpan> {
+    if parsed_timespans.is_empty() {
         return parsed_timespans;
     }
 
-    let mut it = parsed_timespans.iter().peekable();
-    let mut curr: NaiveTimeSpan = it.next().unwrap().clone();
-    let mut next: NaiveTimeSpan = it.next().unwrap().clone();
+    parsed_timespans.sort_by_key(|span| span.start);
 
-    loop {
-        while curr.end == next.start && it.peek().is_some() {
-            let mut merged = curr.union(&next).unwrap().clone();
-            curr = merged;
-            next = it.next().unwrap().clone();
-        }
-        result.push(curr.clone());
-        if it.peek().is_none() {
-            break;
+    let mut it = parsed_timespans.into_iter();
+    let mut current = it.next().unwrap();
+    let mut result = vec![];
+
+    for next in it {
+        if next.start <= current.end {
+            if next.start == current.end {
+                current = current.union(&next).unwrap();
+            } else {
+                current.end = current.end.max(next.end);
+            }
+        } else {
+            result.push(current);
+            current = next;
         }
     }
-    return result;
+    result.push(current);
 
-    // let mut i = 1;
-    // while i < parsed_timespans.len() {
-    //     let prev = parsed_timespans.get(i - 1).unwrap();
-    //     let mut curr = parsed_timespans.get(i).unwrap();
-
-    //     if prev.end == curr.start {
-    //         curr = &prev.union(curr).unwrap();
-    //         while (i < parsed_timespans.len())
-    //         result.push(prev.union(curr).unwrap());
-    //     } else {
-    //         result.push(prev.to_owned());
-    //     }
-    // }
-    // for i in 1..parsed_timespans.len() {
-    //     let prev = parsed_timespans.get(i - 1).unwrap();
-    //     let curr = parsed_timespans.get(i).unwrap();
-
-    //     if prev.end == curr.start {
-    //         result.push(prev.union(curr).unwrap());
-    //     } else {
-    //         result.push(prev.to_owned());
-    //     }
-    // }
+    result
 }
 
 //Getting the court name from the table header
-fn get_court_num(tbodies: scraper::ElementRef, activity: &Activity) -> u32 {
-    let name_prefix_len = match activity {
-        Activity::BEACH => 5,
-        Activity::TENNIS => 6,
-        Activity::PICKLE => todo!(),
-    };
-
+fn get_court_num(tbodies: scraper::ElementRef) -> u32 {
     let court_num_select = Selector::parse("th").unwrap();
 
-    let court_num = tbodies
+    let court_name = tbodies
         .select(&court_num_select)
         .next()
         .unwrap()
         .text()
         .collect::<Vec<_>>()
         .concat();
-    return court_num[name_prefix_len..].parse::<u32>().unwrap();
+
+    //Court name prefixes differ per activity, so skip to the first digit.
+    let digit_start = court_name
+        .find(|c: char| c.is_ascii_digit())
+        .expect("court name should contain a number");
+
+    return court_name[digit_start..].parse::<u32>().unwrap();
+}
+
+/// Shared client used for all scraping requests, sending `USER_AGENT` on every one.
+fn http_client() -> &'static Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        Client::builder()
+            .user_agent(USER_AGENT)
+            .build()
+            .expect("failed to build http client")
+    })
 }
 
+/// Performs a GET, retrying non-success responses and transport errors with
+/// exponential backoff (5s, 10s, 20s, capped). Honors a `Retry-After` header
+/// if the server sends one.
 fn perform_request(url: &str) -> Result<Response, Error> {
-    return get(url);
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+
+        match http_client().get(url).send() {
+            Ok(response) if response.status().is_success() => return Ok(response),
+            Ok(response) => {
+                if attempt >= MAX_REQUEST_ATTEMPTS {
+                    return response.error_for_status();
+                }
+                let wait = retry_after(&response).unwrap_or_else(|| backoff_duration(attempt));
+                println!(
+                    "Request to {} failed with status {}, retrying in {:?} (attempt {}/{})",
+                    url,
+                    response.status(),
+                    wait,
+                    attempt,
+                    MAX_REQUEST_ATTEMPTS
+                );
+                sleep(wait);
+            }
+            Err(e) => {
+                if attempt >= MAX_REQUEST_ATTEMPTS {
+                    return Err(e);
+                }
+                let wait = backoff_duration(attempt);
+                println!(
+                    "Request to {} errored: {}, retrying in {:?} (attempt {}/{})",
+                    url, e, wait, attempt, MAX_REQUEST_ATTEMPTS
+                );
+                sleep(wait);
+            }
+        }
+    }
+}
+
+fn backoff_duration(attempt: u32) -> Duration {
+    let secs = BASE_BACKOFF_SECS << (attempt - 1).min(2);
+    Duration::from_secs(secs.min(MAX_BACKOFF_SECS))
+}
+
+fn retry_after(response: &Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
 }